@@ -1,7 +1,7 @@
 use std::{collections::HashMap, hash::Hash};
 
 use rand::Rng;
-use reinforcement_learning::rl::train_monte_carlo_exploring_starts;
+use reinforcement_learning::rl::{MonteCarloConfig, train_monte_carlo_exploring_starts};
 
 /// A non-terminal Blackjack state.
 #[derive(Clone, Copy, Eq, PartialEq)]
@@ -132,7 +132,12 @@ fn main() {
                 },
             );
         },
+        MonteCarloConfig {
+            gamma: 1.0,
+            every_visit: false,
+        },
         &mut rng,
+        None,
     );
     display_policy(policy);
     // The optimal policy should be...
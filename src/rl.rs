@@ -1,37 +1,137 @@
 use std::{collections::HashMap, hash::Hash};
 
 use indicatif::ProgressIterator;
-use num::Rational64;
 use rand::Rng;
+use serde::Serialize;
+
+/// Configuration for [`train_monte_carlo_exploring_starts`].
+pub struct MonteCarloConfig {
+    /// Discount factor applied to future rewards while accumulating the
+    /// return for each step of an episode. Use `1.0` for undiscounted,
+    /// terminal-reward-only tasks like Blackjack.
+    pub gamma: f64,
+    /// If `true`, fold every occurrence of a `(state, action)` pair within
+    /// an episode into the Q running mean (every-visit). If `false`, only
+    /// the first occurrence counts (first-visit).
+    pub every_visit: bool,
+}
+
+/// The state space, action space, and transition dynamics of an
+/// environment that a Monte Carlo control or prediction routine can learn
+/// against.
+///
+/// Implement this (instead of passing bare closures) to carry environment
+/// state across steps, e.g. a finite deck instead of an infinite one.
+pub trait Environment<R: Rng> {
+    type State: Copy + Eq + Hash;
+    type Action: Copy;
+
+    /// Apply `action` in `state`, returning the resulting state (or `None`
+    /// if the episode terminated) and the reward received.
+    fn step(
+        &self,
+        state: Self::State,
+        action: Self::Action,
+        rng: &mut R,
+    ) -> (Option<Self::State>, i64);
+}
+
+/// An [`Environment`] that can also produce an exploring start: a
+/// random `(state, action)` pair used to seed Monte Carlo ES (Exploring
+/// Starts) episodes.
+pub trait ExploringStarts<R: Rng>: Environment<R> {
+    fn exploring_start(&mut self, rng: &mut R) -> (Self::State, Self::Action);
+}
+
+/// A fixed mapping from states to actions, as used by
+/// [`evaluate_monte_carlo_prediction`].
+///
+/// Any `Fn(State) -> Action` closure already implements this, so the
+/// closure-based entry points below need no changes to call this trait's
+/// generic counterpart.
+pub trait Policy<State, Action> {
+    fn action(&self, state: State) -> Action;
+}
+
+impl<State, Action, F: Fn(State) -> Action> Policy<State, Action> for F {
+    fn action(&self, state: State) -> Action {
+        self(state)
+    }
+}
+
+/// Update `q[state][action]`'s incremental sample-average return and keep
+/// `policy[state]` at the argmax over `q[state]`, tracking the current best
+/// action/value per state in `best` to avoid rescanning every action on
+/// every visit.
+///
+/// Sample means are not monotonic (a later visit can lower a previously
+/// high mean), so a cached best can only be trusted as long as the action
+/// it names isn't the one that just changed. When `action` *is* the cached
+/// best, its mean may have just dropped below another action's, so `best`
+/// is re-derived from scratch; otherwise the cached best is still valid and
+/// only needs updating if `action` has overtaken it.
+fn update_q<State: Copy + Eq + Hash, Action: Copy + Eq + Hash>(
+    q: &mut HashMap<State, HashMap<Action, (f64, u64)>>,
+    policy: &mut HashMap<State, Action>,
+    best: &mut HashMap<State, (Action, f64)>,
+    (state, action): (State, Action),
+    returns: f64,
+) {
+    let qq = q.entry(state).or_default();
+    let (mean, count) = qq.entry(action).or_insert((0.0, 0));
+    *count += 1;
+    *mean += (returns - *mean) / *count as f64;
+    let value = *mean;
+
+    let needs_rescan = matches!(best.get(&state), Some(&(best_action, _)) if best_action == action);
+    if needs_rescan {
+        let (&best_action, &(best_mean, _)) =
+            qq.iter().max_by(|a, b| a.1.0.total_cmp(&b.1.0)).unwrap();
+        best.insert(state, (best_action, best_mean));
+        policy.insert(state, best_action);
+    } else {
+        let is_new_best = match best.get(&state) {
+            Some(&(_, best_mean)) => value > best_mean,
+            None => true,
+        };
+        if is_new_best {
+            best.insert(state, (action, value));
+            policy.insert(state, action);
+        }
+    }
+}
 
 /// Monte Carlo ES (Exploring Starts), for estimating optimal policy,
 /// from Sutton & Barto 2nd Ed. Section 5.3.
-pub fn train_monte_carlo_exploring_starts<
-    R: Rng,
-    State: Copy + Eq + Hash,
-    Action: Copy + Default + Eq + Hash,
-    ExploreStarts: Fn(&mut R) -> (State, Action),
-    NextState: Fn((State, Action), &mut R) -> (Option<State>, i64),
->(
+///
+/// Operates generically over any [`ExploringStarts`] environment.
+/// [`train_monte_carlo_exploring_starts`] is a thin closure-based adapter
+/// over this for callers who don't need to carry environment state.
+pub fn train_monte_carlo_exploring_starts_env<R: Rng, E: ExploringStarts<R>>(
     episodes: usize,
-    explore_starts: ExploreStarts,
-    next_state: NextState,
+    env: &mut E,
+    config: MonteCarloConfig,
     rng: &mut R,
-) -> HashMap<State, Action> {
+    mut trace: Option<&mut dyn FnMut(&[(E::State, E::Action, i64)])>,
+) -> HashMap<E::State, E::Action>
+where
+    E::Action: Default + Eq + Hash,
+{
     let mut policy = HashMap::new();
-    let mut q: HashMap<State, HashMap<Action, Rational64>> = HashMap::new();
+    let mut q: HashMap<E::State, HashMap<E::Action, (f64, u64)>> = HashMap::new();
+    let mut best: HashMap<E::State, (E::Action, f64)> = HashMap::new();
     for _ in (0..episodes).progress() {
-        let mut state_action = explore_starts(rng);
-        let mut episode: Vec<((State, Action), i64)> = Vec::new();
+        let mut state_action = env.exploring_start(rng);
+        let mut episode: Vec<((E::State, E::Action), i64)> = Vec::new();
         loop {
-            let (next_state, reward) = next_state(state_action, rng);
+            let (next_state, reward) = env.step(state_action.0, state_action.1, rng);
             episode.push((state_action, reward));
             if let Some(state) = next_state {
                 state_action = (
                     state,
                     match policy.get(&state) {
                         Some(&action) => action,
-                        None => Action::default(),
+                        None => E::Action::default(),
                     },
                 );
             } else {
@@ -39,22 +139,379 @@ pub fn train_monte_carlo_exploring_starts<
             }
         }
 
-        let mut returns = 0;
-        let mut first_visits: HashMap<(State, Action), i64> = HashMap::new();
-        for &(state_action, reward) in episode.iter().rev() {
-            returns += reward;
-            first_visits.insert(state_action, returns);
+        if let Some(trace) = trace.as_deref_mut() {
+            let steps: Vec<(E::State, E::Action, i64)> = episode
+                .iter()
+                .map(|&((state, action), reward)| (state, action, reward))
+                .collect();
+            trace(&steps);
+        }
+
+        let mut returns = 0.0;
+        if config.every_visit {
+            for &(state_action, reward) in episode.iter().rev() {
+                returns = config.gamma * returns + reward as f64;
+                update_q(&mut q, &mut policy, &mut best, state_action, returns);
+            }
+        } else {
+            let mut first_visits: HashMap<(E::State, E::Action), f64> = HashMap::new();
+            for &(state_action, reward) in episode.iter().rev() {
+                returns = config.gamma * returns + reward as f64;
+                first_visits.insert(state_action, returns);
+            }
+
+            for (&state_action, &returns) in first_visits.iter() {
+                update_q(&mut q, &mut policy, &mut best, state_action, returns);
+            }
+        }
+    }
+    policy
+}
+
+/// Adapts a pair of `explore_starts`/`next_state` closures into an
+/// [`ExploringStarts`] environment, so [`train_monte_carlo_exploring_starts`]
+/// can delegate to [`train_monte_carlo_exploring_starts_env`].
+struct ExploringStartsClosures<ExploreStarts, NextState> {
+    explore_starts: ExploreStarts,
+    next_state: NextState,
+}
+
+impl<R, State, Action, ExploreStarts, NextState> Environment<R>
+    for ExploringStartsClosures<ExploreStarts, NextState>
+where
+    R: Rng,
+    State: Copy + Eq + Hash,
+    Action: Copy + Eq + Hash,
+    ExploreStarts: Fn(&mut R) -> (State, Action),
+    NextState: Fn((State, Action), &mut R) -> (Option<State>, i64),
+{
+    type State = State;
+    type Action = Action;
+
+    fn step(&self, state: State, action: Action, rng: &mut R) -> (Option<State>, i64) {
+        (self.next_state)((state, action), rng)
+    }
+}
+
+impl<R, State, Action, ExploreStarts, NextState> ExploringStarts<R>
+    for ExploringStartsClosures<ExploreStarts, NextState>
+where
+    R: Rng,
+    State: Copy + Eq + Hash,
+    Action: Copy + Eq + Hash,
+    ExploreStarts: Fn(&mut R) -> (State, Action),
+    NextState: Fn((State, Action), &mut R) -> (Option<State>, i64),
+{
+    fn exploring_start(&mut self, rng: &mut R) -> (State, Action) {
+        (self.explore_starts)(rng)
+    }
+}
+
+/// Monte Carlo ES (Exploring Starts), for estimating optimal policy,
+/// from Sutton & Barto 2nd Ed. Section 5.3.
+pub fn train_monte_carlo_exploring_starts<
+    R: Rng,
+    State: Copy + Eq + Hash,
+    Action: Copy + Default + Eq + Hash,
+    ExploreStarts: Fn(&mut R) -> (State, Action),
+    NextState: Fn((State, Action), &mut R) -> (Option<State>, i64),
+>(
+    episodes: usize,
+    explore_starts: ExploreStarts,
+    next_state: NextState,
+    config: MonteCarloConfig,
+    rng: &mut R,
+    trace: Option<&mut dyn FnMut(&[(State, Action, i64)])>,
+) -> HashMap<State, Action> {
+    let mut env = ExploringStartsClosures {
+        explore_starts,
+        next_state,
+    };
+    train_monte_carlo_exploring_starts_env(episodes, &mut env, config, rng, trace)
+}
+
+/// Adapts a `next_state` closure into an [`Environment`], so
+/// [`train_monte_carlo_off_policy`] and [`evaluate_monte_carlo_prediction`]
+/// can delegate to their generic counterparts.
+struct ClosureEnvironment<NextState> {
+    next_state: NextState,
+}
+
+impl<R, State, Action, NextState> Environment<R> for ClosureEnvironment<NextState>
+where
+    R: Rng,
+    State: Copy + Eq + Hash,
+    Action: Copy,
+    NextState: Fn((State, Action), &mut R) -> (Option<State>, i64),
+{
+    type State = State;
+    type Action = Action;
+
+    fn step(&self, state: State, action: Action, rng: &mut R) -> (Option<State>, i64) {
+        (self.next_state)((state, action), rng)
+    }
+}
+
+/// Off-policy Monte Carlo control with incremental weighted importance
+/// sampling, for estimating the optimal policy while following a separate
+/// (soft) behavior policy, from Sutton & Barto 2nd Ed. Section 5.7.
+///
+/// Unlike [`train_monte_carlo_exploring_starts`], this does not require
+/// exploring starts: episodes are generated by repeatedly sampling
+/// `behavior`, which returns the action it took along with its probability
+/// `b(a|s)` under the behavior policy. The target policy learned is always
+/// greedy with respect to `q`. Operates generically over any
+/// [`Environment`]; [`train_monte_carlo_off_policy`] is a thin closure-based
+/// adapter over this.
+pub fn train_monte_carlo_off_policy_env<
+    R: Rng,
+    E: Environment<R>,
+    Start: Fn(&mut R) -> E::State,
+    Behavior: Fn(E::State, &mut R) -> (E::Action, f64),
+>(
+    episodes: usize,
+    env: &E,
+    start: Start,
+    behavior: Behavior,
+    gamma: f64,
+    rng: &mut R,
+    mut trace: Option<&mut dyn FnMut(&[(E::State, E::Action, i64)])>,
+) -> HashMap<E::State, E::Action>
+where
+    E::Action: Eq + Hash,
+{
+    let mut policy: HashMap<E::State, E::Action> = HashMap::new();
+    let mut q: HashMap<E::State, HashMap<E::Action, f64>> = HashMap::new();
+    let mut c: HashMap<E::State, HashMap<E::Action, f64>> = HashMap::new();
+
+    for _ in (0..episodes).progress() {
+        let mut state = start(rng);
+        let mut episode: Vec<(E::State, E::Action, i64, f64)> = Vec::new();
+        loop {
+            let (action, probability) = behavior(state, rng);
+            let (next_state, reward) = env.step(state, action, rng);
+            episode.push((state, action, reward, probability));
+            match next_state {
+                Some(next_state) => state = next_state,
+                None => break,
+            }
+        }
+
+        if let Some(trace) = trace.as_deref_mut() {
+            let steps: Vec<(E::State, E::Action, i64)> = episode
+                .iter()
+                .map(|&(state, action, reward, _probability)| (state, action, reward))
+                .collect();
+            trace(&steps);
         }
 
-        for (&(state, action), &returns) in first_visits.iter() {
+        let mut g = 0.0;
+        let mut w = 1.0;
+        for &(state, action, reward, probability) in episode.iter().rev() {
+            g = gamma * g + reward as f64;
+
+            let c_value = c.entry(state).or_default().entry(action).or_insert(0.0);
+            *c_value += w;
             let qq = q.entry(state).or_default();
-            qq.entry(action)
-                .and_modify(|f| *f = Rational64::new_raw(f.numer() + returns, f.denom() + 1))
-                .or_insert_with(|| Rational64::from_integer(returns));
+            let q_value = qq.entry(action).or_insert(0.0);
+            *q_value += (w / *c_value) * (g - *q_value);
 
-            let (&action, _) = qq.iter().max_by_key(|&(_, value)| value).unwrap();
-            policy.insert(state, action);
+            let (&best_action, _) = qq.iter().max_by(|a, b| a.1.total_cmp(b.1)).unwrap();
+            policy.insert(state, best_action);
+
+            if action != best_action {
+                break;
+            }
+            w *= 1.0 / probability;
+        }
+    }
+    policy
+}
+
+/// Off-policy Monte Carlo control with incremental weighted importance
+/// sampling, for estimating the optimal policy while following a separate
+/// (soft) behavior policy, from Sutton & Barto 2nd Ed. Section 5.7.
+pub fn train_monte_carlo_off_policy<
+    R: Rng,
+    State: Copy + Eq + Hash,
+    Action: Copy + Eq + Hash,
+    Start: Fn(&mut R) -> State,
+    Behavior: Fn(State, &mut R) -> (Action, f64),
+    NextState: Fn((State, Action), &mut R) -> (Option<State>, i64),
+>(
+    episodes: usize,
+    start: Start,
+    behavior: Behavior,
+    next_state: NextState,
+    gamma: f64,
+    rng: &mut R,
+    trace: Option<&mut dyn FnMut(&[(State, Action, i64)])>,
+) -> HashMap<State, Action> {
+    let env = ClosureEnvironment { next_state };
+    train_monte_carlo_off_policy_env(episodes, &env, start, behavior, gamma, rng, trace)
+}
+
+/// First-visit Monte Carlo prediction, for estimating the state-value
+/// function of a fixed policy, from Sutton & Barto 2nd Ed. Section 5.1.
+///
+/// Unlike the control routines above, this does not learn a policy: it
+/// rolls out `episodes` under the fixed `policy` and returns the averaged
+/// first-visit return for every state encountered, discounted by `gamma`.
+/// Operates generically over any [`Environment`] and [`Policy`];
+/// [`evaluate_monte_carlo_prediction`] is a thin closure-based adapter over
+/// this.
+pub fn evaluate_monte_carlo_prediction_env<
+    R: Rng,
+    E: Environment<R>,
+    Start: Fn(&mut R) -> E::State,
+    P: Policy<E::State, E::Action>,
+>(
+    episodes: usize,
+    env: &E,
+    start: Start,
+    policy: P,
+    gamma: f64,
+    rng: &mut R,
+    mut trace: Option<&mut dyn FnMut(&[(E::State, E::Action, i64)])>,
+) -> HashMap<E::State, f64> {
+    let mut returns: HashMap<E::State, (f64, u64)> = HashMap::new();
+
+    for _ in (0..episodes).progress() {
+        let mut state = start(rng);
+        let mut episode: Vec<(E::State, E::Action, i64)> = Vec::new();
+        loop {
+            let action = policy.action(state);
+            let (next_state, reward) = env.step(state, action, rng);
+            episode.push((state, action, reward));
+            match next_state {
+                Some(next_state) => state = next_state,
+                None => break,
+            }
         }
+
+        if let Some(trace) = trace.as_deref_mut() {
+            trace(&episode);
+        }
+
+        let mut g = 0.0;
+        let mut first_visits: HashMap<E::State, f64> = HashMap::new();
+        for &(state, _action, reward) in episode.iter().rev() {
+            g = gamma * g + reward as f64;
+            first_visits.insert(state, g);
+        }
+
+        for (state, g) in first_visits {
+            let (sum, count) = returns.entry(state).or_default();
+            *sum += g;
+            *count += 1;
+        }
+    }
+
+    returns
+        .into_iter()
+        .map(|(state, (sum, count))| (state, sum / count as f64))
+        .collect()
+}
+
+/// First-visit Monte Carlo prediction, for estimating the state-value
+/// function of a fixed policy, from Sutton & Barto 2nd Ed. Section 5.1.
+pub fn evaluate_monte_carlo_prediction<
+    R: Rng,
+    State: Copy + Eq + Hash,
+    Action: Copy,
+    Start: Fn(&mut R) -> State,
+    Policy: Fn(State) -> Action,
+    NextState: Fn((State, Action), &mut R) -> (Option<State>, i64),
+>(
+    episodes: usize,
+    start: Start,
+    policy: Policy,
+    next_state: NextState,
+    gamma: f64,
+    rng: &mut R,
+    trace: Option<&mut dyn FnMut(&[(State, Action, i64)])>,
+) -> HashMap<State, f64> {
+    let env = ClosureEnvironment { next_state };
+    evaluate_monte_carlo_prediction_env(episodes, &env, start, policy, gamma, rng, trace)
+}
+
+/// Serialize an episode trace, as collected by the `trace` callback of the
+/// training functions above, to JSON Lines: one `(state, action, reward)`
+/// step per line.
+///
+/// This is a convenience for feeding traces into external tooling, e.g. to
+/// animate learning or compute per-episode return diagnostics.
+pub fn trace_to_json_lines<State: Serialize, Action: Serialize>(
+    episode: &[(State, Action, i64)],
+) -> serde_json::Result<String> {
+    episode
+        .iter()
+        .map(serde_json::to_string)
+        .collect::<serde_json::Result<Vec<_>>>()
+        .map(|lines| lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A single-state, single-step bandit: action `true` always pays more
+    /// than `false`. Every episode is exactly one step, so the incremental
+    /// weighted-importance-sampling weight resets to 1 at the start of each
+    /// episode and the learned `q` values are just a plain average of each
+    /// action's (constant) reward -- hand-checkable without relying on the
+    /// behavior policy's random draws.
+    #[test]
+    fn off_policy_control_learns_the_better_bandit_arm() {
+        let env = ClosureEnvironment {
+            next_state: |(_state, action): ((), bool), _rng: &mut rand::rngs::mock::StepRng| {
+                (None, if action { 10 } else { 0 })
+            },
+        };
+        // Alternate the behavior action deterministically so both arms are
+        // sampled, instead of relying on an RNG draw to cover both.
+        let next_behavior_is_hit = std::cell::Cell::new(true);
+        let behavior = |_state: (), _rng: &mut rand::rngs::mock::StepRng| {
+            let action = next_behavior_is_hit.get();
+            next_behavior_is_hit.set(!action);
+            (action, 0.5)
+        };
+
+        let mut rng = rand::rngs::mock::StepRng::new(0, 1);
+        let policy = train_monte_carlo_off_policy_env(
+            4,
+            &env,
+            |_rng| (),
+            behavior,
+            1.0,
+            &mut rng,
+            None,
+        );
+
+        assert_eq!(policy[&()], true);
+    }
+
+    /// A two-state chain, A -> B -> terminal, with fixed rewards and no
+    /// choice of action, so the discounted first-visit return is exact:
+    /// V(A) = r_a + gamma * r_b, V(B) = r_b. With gamma < 1.0 here,
+    /// V(A) = 4 + 0.5 * 1 = 4.5 has a fractional part, so accumulating
+    /// returns as rounded integers would give 5 instead and fail.
+    #[test]
+    fn prediction_discounts_returns_without_truncating_them() {
+        let env = ClosureEnvironment {
+            next_state: |(state, _action): (u8, ()), _rng: &mut rand::rngs::mock::StepRng| {
+                match state {
+                    0 => (Some(1), 4),
+                    _ => (None, 1),
+                }
+            },
+        };
+
+        let mut rng = rand::rngs::mock::StepRng::new(0, 1);
+        let values =
+            evaluate_monte_carlo_prediction_env(3, &env, |_rng| 0, |_state| (), 0.5, &mut rng, None);
+
+        assert_eq!(values[&0], 4.5);
+        assert_eq!(values[&1], 1.0);
     }
-    return policy;
 }